@@ -2,25 +2,53 @@
 //! as the necessary functions lock() and unlock(). It also declares the struct Lock which 
 //! is used to hold necessary information about each lock a client acquires. 
 
-use redis::{Client, Connection, RedisResult, cmd, Value};
+use redis::{Client, Connection, RedisResult, RedisError, cmd, Value};
 use types::{RedlockResult, Error};
 use time::precise_time_s;
 use std::time::Duration;
+use std::thread;
 use std::thread::sleep;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
 use rand::{thread_rng, Rng};
 
+/// Lua source for `unlock_instance`
+const UNLOCK_SCRIPT : &'static str =
+    "if redis.call('get',KEYS[1]) == ARGV[1] then return redis.call('del',KEYS[1]) else return 0 end";
+/// Lua source for `extend_instance`
+const EXTEND_SCRIPT : &'static str =
+    "if redis.call('get',KEYS[1]) == ARGV[1] then return redis.call('pexpire',KEYS[1],ARGV[2]) else return 0 end";
+
+/// one Redis master: a live connection plus the `Client` used to reopen it
+struct Server {
+    /// used to reopen `conn` if the master drops the TCP connection
+    client: Client,
+    /// the live connection, re-opened in place on a connection-level failure
+    conn: Mutex<Connection>,
+}
+
+/// state shared between a `Redlock` and any `LockGuard`/watchdog threads it has handed out
+struct Shared {
+    /// list of connections with Master Nodes
+    servers: Vec<Arc<Server>>,
+    /// number of locks needed
+    quorum: i32,
+    /// clock drift factor
+    clock_drift_factor: f32,
+    /// SHA1 digest of `UNLOCK_SCRIPT`, pre-loaded via `SCRIPT LOAD`
+    unlock_sha: String,
+    /// SHA1 digest of `EXTEND_SCRIPT`, pre-loaded via `SCRIPT LOAD`
+    extend_sha: String,
+}
+
 /// Distributed Lock Manager class object
 pub struct Redlock {
-    /// list of connections with Master Nodes
-    servers: Vec<Connection>,
+    /// connections and the script/quorum state shared with any outstanding `LockGuard`s
+    shared: Arc<Shared>,
     /// no. of retries to secure locks
     retry_count: i32,
     /// time delay between each retry
     retry_delay: f32,
-    /// number of locks needed
-    quorum: i32,
-    /// clock drift factor
-    clock_drift_factor: f32,
 }
 
 /// Represents the Lock a client holds 
@@ -36,19 +64,19 @@ pub struct Lock {
 }
 
 impl Redlock {
-    /// instantialize a lock manager with a vector of URLs (format: redis://host:port/db) 
+    /// instantialize a lock manager with a vector of URLs (format: redis://host:port/db)
     /// for each Redis master node, and optionally specify a retry count and delay between
-    /// each retry. If None is given, the default will be 3 and 0.2s respectively
+    /// each retry. If None is given, the default will be 3 and 0.2s respectively. Quorum is
+    /// a majority of the URLs given, so the DLM can still be built with some unreachable.
     pub fn dlm(urls : Vec<String>, retry_count: Option<i32>, retry_delay: Option<f32>)
         -> RedlockResult<Redlock> {
+        let n = urls.len() as i32;
+        let quorum = n / 2 + 1;
         let mut servers = Vec::new();
-        let quorum = urls.len() as i32;
         for u in urls {
-            let client_res = Client::open(&*u);
-            if client_res.is_ok() {
-                let con_res = client_res.unwrap().get_connection();
-                if con_res.is_ok() {
-                    servers.push(con_res.unwrap());
+            if let Ok(client) = Client::open(&*u) {
+                if let Ok(con) = client.get_connection() {
+                    servers.push(Server { client: client, conn: Mutex::new(con) });
                 }
             }
         }
@@ -65,12 +93,28 @@ impl Redlock {
 
         };
         let cdf = 0.01;
-        Ok(Redlock { 
-            servers: servers, 
-            retry_count: rc, 
+        let mut unlock_sha = String::new();
+        let mut extend_sha = String::new();
+        for server in &servers {
+            let conn = server.conn.lock().unwrap();
+            if let Ok(sha) = load_script(&conn, UNLOCK_SCRIPT) {
+                unlock_sha = sha;
+            }
+            if let Ok(sha) = load_script(&conn, EXTEND_SCRIPT) {
+                extend_sha = sha;
+            }
+        }
+        let servers = servers.into_iter().map(Arc::new).collect();
+        Ok(Redlock {
+            shared: Arc::new(Shared {
+                servers: servers,
+                quorum: quorum,
+                clock_drift_factor: cdf,
+                unlock_sha: unlock_sha,
+                extend_sha: extend_sha,
+            }),
+            retry_count: rc,
             retry_delay: rd,
-            quorum: quorum,
-            clock_drift_factor: cdf,
         })
     }
 
@@ -81,49 +125,63 @@ impl Redlock {
     }
 
    /// locks resource specified by res_name for ttl in miliseconds
-    pub fn lock(&mut self, res_name: String, ttl: i32) -> RedlockResult<Lock> {
+    pub fn lock(&self, res_name: String, ttl: i32) -> RedlockResult<Lock> {
         let mut retry = 0;
         let val = self.get_unique_id();
-        let drift : i32 = (((ttl as f32) * self.clock_drift_factor) as i32) + 2;
         while retry < self.retry_count {
-            let mut n = 0;
-            let start_time : i32 = (precise_time_s() * 1000.0) as i32;
-            for server in &mut self.servers {
-                let res = lock_instance(server, &res_name, &val, ttl);
-                if res.is_ok() {
-                    n = n + 1;
-                }
-            }
-            let elapsed_time : i32 = ((precise_time_s() * 1000.0) as i32) - start_time;
-            let validity = ttl - elapsed_time - drift;
-            let start_time = precise_time_s();
-            if validity > 0 && n >= self.quorum {
-                // lock successful!
-                return Ok(Lock::new(validity, res_name, val, start_time));
-            }  else {
-                for server in &mut self.servers {
-                    let res = unlock_instance(server, &res_name, &val); 
-                    if res.is_err() { 
-                        return Err(Error::RedlockConn);
-                    }
+            match lock_all(&self.shared, &res_name, &val, ttl) {
+                Ok(validity) => return Ok(Lock::new(validity, res_name, val, precise_time_s())),
+                Err(Error::RedlockConn) => return Err(Error::RedlockConn),
+                Err(_) => {
+                    retry = retry + 1;
+                    // sleep for retry_delay
+                    sleep(Duration::from_millis((self.retry_delay * 1000.0) as u64));
                 }
-                retry = retry + 1;
-                // sleep for retry_delay
-                sleep(Duration::from_millis((self.retry_delay as u64) * 1000));
             }
         }
         Err(Error::CannotObtainLock)
     }
-    /// unlocks resource held by Lock
-    pub fn unlock(&mut self, lock: Lock) -> RedlockResult<()> {
-        for server in &mut self.servers {
-            let res = unlock_instance(server, &lock.resource, &lock.key);
-            if res.is_err() {
-                return Err(Error::RedlockConn);
+    /// like `lock()`, but retries with a jittered delay until `deadline` (in seconds) passes
+    pub fn lock_until(&self, res_name: String, ttl: i32, deadline: f64) -> RedlockResult<Lock> {
+        let val = self.get_unique_id();
+        while precise_time_s() < deadline {
+            match lock_all(&self.shared, &res_name, &val, ttl) {
+                Ok(validity) => return Ok(Lock::new(validity, res_name, val, precise_time_s())),
+                Err(Error::RedlockConn) => return Err(Error::RedlockConn),
+                Err(_) => {
+                    let jitter = if self.retry_delay > 0.0 {
+                        thread_rng().gen_range(0.0, self.retry_delay)
+                    } else {
+                        0.0
+                    };
+                    sleep(Duration::from_millis((jitter * 1000.0) as u64));
+                }
             }
         }
+        Err(Error::CannotObtainLock)
+    }
+    /// refreshes the TTL of a lock the caller already holds, without releasing and re-acquiring it
+    pub fn extend(&self, lock: &mut Lock, ttl: i32) -> RedlockResult<()> {
+        let validity = extend_all(&self.shared, &lock.resource, &lock.key, ttl)?;
+        lock.validity = validity;
+        lock.start_time = precise_time_s();
         Ok(())
     }
+    /// unlocks resource held by Lock
+    pub fn unlock(&self, lock: Lock) -> RedlockResult<()> {
+        unlock_all(&self.shared, &lock.resource, &lock.key)
+    }
+    /// like `lock()`, but returns a `LockGuard` that releases on drop; if `watchdog` is true
+    /// also spawns a background thread that keeps re-extending the lock until dropped
+    pub fn lock_guard(&self, res_name: String, ttl: i32, watchdog: bool) -> RedlockResult<LockGuard> {
+        let lock = Arc::new(Mutex::new(self.lock(res_name, ttl)?));
+        let watchdog = if watchdog {
+            Some(Watchdog::spawn(self.shared.clone(), lock.clone(), ttl))
+        } else {
+            None
+        };
+        Ok(LockGuard { shared: self.shared.clone(), lock: lock, watchdog: watchdog })
+    }
 }
 
 impl Lock {
@@ -138,12 +196,187 @@ impl Lock {
     }
 }
 
-/// release lock from one server
-fn unlock_instance(server : &Connection, res_name : &str, 
-                   val : &str) -> RedlockResult<()> {
-    let unlock_script = "if redis.call('get',KEYS[1]) == ARGV[1] then return redis.call('del',KEYS[1]) else return 0 end";
-    let res : RedisResult<i32> = cmd("EVAL").arg(unlock_script).arg(1).arg(res_name).arg(val)
+/// RAII wrapper that releases the held lock when dropped
+pub struct LockGuard {
+    shared: Arc<Shared>,
+    lock: Arc<Mutex<Lock>>,
+    watchdog: Option<Watchdog>,
+}
+
+impl LockGuard {
+    /// checks if lock is still valid
+    pub fn still_valid(&self) -> bool {
+        self.lock.lock().unwrap().still_valid()
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if let Some(watchdog) = self.watchdog.take() {
+            watchdog.stop();
+        }
+        let lock = self.lock.lock().unwrap();
+        let _ = unlock_all(&self.shared, &lock.resource, &lock.key);
+    }
+}
+
+/// background thread that re-extends a `LockGuard`'s lock at roughly `validity/3` intervals
+struct Watchdog {
+    stop_tx: Sender<()>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl Watchdog {
+    fn spawn(shared: Arc<Shared>, lock: Arc<Mutex<Lock>>, ttl: i32) -> Watchdog {
+        let (stop_tx, stop_rx) = channel();
+        let handle = thread::spawn(move || {
+            loop {
+                let (resource, key, validity) = {
+                    let l = lock.lock().unwrap();
+                    (l.resource.clone(), l.key.clone(), l.validity)
+                };
+                let interval = Duration::from_millis((validity.max(0) / 3) as u64);
+                if stop_rx.recv_timeout(interval).is_ok() {
+                    break;
+                }
+                match extend_all(&shared, &resource, &key, ttl) {
+                    Ok(new_validity) => {
+                        let mut l = lock.lock().unwrap();
+                        l.validity = new_validity;
+                        l.start_time = precise_time_s();
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+        Watchdog { stop_tx: stop_tx, handle: handle }
+    }
+
+    fn stop(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.handle.join();
+    }
+}
+
+/// runs `f` against a server's connection, reconnecting and retrying once on a connection error
+fn with_reconnect<T, F>(server: &Server, f: F) -> RedlockResult<T>
+    where F: Fn(&Connection) -> RedlockResult<T> {
+    let res = {
+        let conn = server.conn.lock().unwrap();
+        f(&conn)
+    };
+    match res {
+        Err(Error::RedlockConn) => {
+            match server.client.get_connection() {
+                Ok(new_conn) => {
+                    let mut conn = server.conn.lock().unwrap();
+                    *conn = new_conn;
+                    f(&conn)
+                }
+                Err(_) => Err(Error::RedlockConn),
+            }
+        }
+        other => other,
+    }
+}
+
+/// run `lock_instance` against every server and tally the quorum, returning the lock's
+/// remaining validity (accounting for elapsed time and clock drift) on success
+fn lock_all(shared: &Shared, res_name: &str, val: &str, ttl: i32) -> RedlockResult<i32> {
+    let mut n = 0;
+    let drift : i32 = (((ttl as f32) * shared.clock_drift_factor) as i32) + 2;
+    let start_time : i32 = (precise_time_s() * 1000.0) as i32;
+    for server in &shared.servers {
+        let res = with_reconnect(server, |conn| lock_instance(conn, res_name, val, ttl));
+        if res.is_ok() {
+            n = n + 1;
+        }
+    }
+    let elapsed_time : i32 = ((precise_time_s() * 1000.0) as i32) - start_time;
+    let validity = ttl - elapsed_time - drift;
+    if validity > 0 && n >= shared.quorum {
+        Ok(validity)
+    } else {
+        let _ = unlock_all(shared, res_name, val);
+        Err(Error::CannotObtainLock)
+    }
+}
+
+/// run `extend_instance` against every server and tally the quorum, returning the lock's
+/// new remaining validity on success
+fn extend_all(shared: &Shared, resource: &str, key: &str, ttl: i32) -> RedlockResult<i32> {
+    let mut n = 0;
+    let drift : i32 = (((ttl as f32) * shared.clock_drift_factor) as i32) + 2;
+    let start_time : i32 = (precise_time_s() * 1000.0) as i32;
+    for server in &shared.servers {
+        let res = with_reconnect(server, |conn| extend_instance(conn, resource, key, ttl, &shared.extend_sha));
+        if res.is_ok() {
+            n = n + 1;
+        }
+    }
+    let elapsed_time : i32 = ((precise_time_s() * 1000.0) as i32) - start_time;
+    let validity = ttl - elapsed_time - drift;
+    if validity > 0 && n >= shared.quorum {
+        Ok(validity)
+    } else {
+        let _ = unlock_all(shared, resource, key);
+        Err(Error::CannotObtainLock)
+    }
+}
+
+/// run `unlock_instance` against every server
+fn unlock_all(shared: &Shared, resource: &str, key: &str) -> RedlockResult<()> {
+    for server in &shared.servers {
+        let res = with_reconnect(server, |conn| unlock_instance(conn, resource, key, &shared.unlock_sha));
+        if res.is_err() {
+            return Err(Error::RedlockConn);
+        }
+    }
+    Ok(())
+}
+
+/// SCRIPT LOADs a Lua script onto a server and returns its SHA1 digest
+fn load_script(server : &Connection, script : &str) -> RedlockResult<String> {
+    let res : RedisResult<String> = cmd("SCRIPT").arg("LOAD").arg(script).query(server);
+    res.map_err(|_| Error::RedlockConn)
+}
+
+/// true if a Redis error is a NOSCRIPT (the server forgot the SHA1 we sent it)
+fn is_noscript(err : &RedisError) -> bool {
+    format!("{}", err).contains("NOSCRIPT")
+}
+
+/// release lock from one server, falling back from EVALSHA to EVAL on NOSCRIPT
+fn unlock_instance(server : &Connection, res_name : &str,
+                   val : &str, sha : &str) -> RedlockResult<()> {
+    let res : RedisResult<i32> = cmd("EVALSHA").arg(sha).arg(1).arg(res_name).arg(val)
                                     .query(server);
+    let res = match res {
+        Err(ref e) if is_noscript(e) => {
+            let _ = load_script(server, UNLOCK_SCRIPT);
+            cmd("EVAL").arg(UNLOCK_SCRIPT).arg(1).arg(res_name).arg(val).query(server)
+        }
+        other => other,
+    };
+    match res {
+        Ok(1) => Ok(()),
+        Ok(0) => Err(Error::UnlockFailed),
+        _ => Err(Error::RedlockConn),
+    }
+}
+
+/// extend the TTL of a lock held on one server, only if the stored value still matches the key
+fn extend_instance(server : &Connection, res_name : &str, val : &str,
+                   ttl: i32, sha : &str) -> RedlockResult<()> {
+    let res : RedisResult<i32> = cmd("EVALSHA").arg(sha).arg(1).arg(res_name).arg(val)
+                                    .arg(ttl).query(server);
+    let res = match res {
+        Err(ref e) if is_noscript(e) => {
+            let _ = load_script(server, EXTEND_SCRIPT);
+            cmd("EVAL").arg(EXTEND_SCRIPT).arg(1).arg(res_name).arg(val).arg(ttl).query(server)
+        }
+        other => other,
+    };
     match res {
         Ok(1) => Ok(()),
         Ok(0) => Err(Error::UnlockFailed),
@@ -177,20 +410,44 @@ pub fn test_lock_instance() {
 pub fn test_unlock_instance() {
     let client = Client::open("redis://127.0.0.1/").unwrap();
     let con = client.get_connection().unwrap();
+    let sha = load_script(&con, UNLOCK_SCRIPT).unwrap();
     assert!(lock_instance(&con, "unlock_test_res", "uni_val", 30000).is_ok());
-    let res = unlock_instance(&con, "unlock_test_res", "uni_val");
+    let res = unlock_instance(&con, "unlock_test_res", "uni_val", &sha);
     assert!(res.is_ok());
     assert_eq!(res, Ok(()));
-    let res = unlock_instance(&con, "unlock_test_res", "uni_val");
+    let res = unlock_instance(&con, "unlock_test_res", "uni_val", &sha);
     assert!(res.is_err());
 }
 
+#[test]
+pub fn test_extend_instance() {
+    let client = Client::open("redis://127.0.0.1/").unwrap();
+    let con = client.get_connection().unwrap();
+    let sha = load_script(&con, EXTEND_SCRIPT).unwrap();
+    assert!(lock_instance(&con, "extend_test_res", "uni_val", 1000).is_ok());
+    assert!(extend_instance(&con, "extend_test_res", "uni_val", 30000, &sha).is_ok());
+    assert!(extend_instance(&con, "extend_test_res", "wrong_val", 30000, &sha).is_err());
+}
+
+#[test]
+pub fn test_with_reconnect() {
+    let client = Client::open("redis://127.0.0.1/").unwrap();
+    let con = client.get_connection().unwrap();
+    let sha = load_script(&con, UNLOCK_SCRIPT).unwrap();
+    let server = Server { client: client, conn: Mutex::new(con) };
+    assert!(with_reconnect(&server, |conn| lock_instance(conn, "reconnect_test_res", "uni_val", 30000)).is_ok());
+    assert!(with_reconnect(&server, |conn| unlock_instance(conn, "reconnect_test_res", "uni_val", &sha)).is_ok());
+}
+
 
 #[cfg(test)]
 mod test{
     use super::Redlock;
     use redis;
     use redis::{RedisResult, Value};
+    use time::precise_time_s;
+    use std::thread::sleep;
+    use std::time::Duration;
 
     #[test]
     pub fn redis_check() {
@@ -205,13 +462,41 @@ mod test{
 
     #[test]
     pub fn single_server_lock() {
-        let mut dlm = Redlock::dlm(vec!["redis://127.0.0.1".to_string()], None, None).unwrap();
+        let dlm = Redlock::dlm(vec!["redis://127.0.0.1".to_string()], None, None).unwrap();
         let my_lock = dlm.lock("my_resource_name".to_string(), 5000);
         assert!(my_lock.is_ok());
         let lock_should_fail = dlm.lock("my_resource_name".to_string(), 4000);
         assert!(lock_should_fail.is_err());
     }
 
+    #[test]
+    pub fn extend_lock() {
+        let dlm = Redlock::dlm(vec!["redis://127.0.0.1".to_string()], None, None).unwrap();
+        let mut lock = dlm.lock("extend_resource_name".to_string(), 1000).unwrap();
+        assert!(dlm.extend(&mut lock, 30000).is_ok());
+        assert!(lock.still_valid());
+    }
+
+    #[test]
+    pub fn lock_until_deadline() {
+        let dlm = Redlock::dlm(vec!["redis://127.0.0.1".to_string()], None, None).unwrap();
+        let _held = dlm.lock("deadline_resource_name".to_string(), 30000).unwrap();
+        let deadline = precise_time_s() + 0.3;
+        let result = dlm.lock_until("deadline_resource_name".to_string(), 1000, deadline);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    pub fn lock_guard_watchdog() {
+        let dlm = Redlock::dlm(vec!["redis://127.0.0.1".to_string()], None, None).unwrap();
+        {
+            let guard = dlm.lock_guard("guard_resource_name".to_string(), 1000, true).unwrap();
+            sleep(Duration::from_millis(1500));
+            assert!(guard.still_valid());
+        }
+        assert!(dlm.lock("guard_resource_name".to_string(), 1000).is_ok());
+    }
+
     /*
     #[test]
     pub fn missing_server() {
@@ -225,7 +510,7 @@ mod test{
                         "redis://127.0.0.1:6379".to_string(),
                         "redis://127.0.0.1:6111".to_string()];
 
-        let mut dlm = Redlock::dlm(urls, None, None).unwrap();
+        let dlm = Redlock::dlm(urls, None, None).unwrap();
         let lock = dlm.lock("multi_lock".to_string(), 10000);
         assert!(lock.is_ok());
         let lock = dlm.lock("multi_lock".to_string(), 10000);